@@ -8,9 +8,133 @@ use web_sys::{Document, Node};
 
 use super::{AppRunner, WebRunner};
 
+/// The IME state for one frame, read from egui's platform output and handed to
+/// [`TextAgent::update`].
+#[derive(Clone, Default)]
+pub struct ImeUpdate {
+    /// Where egui wants the IME to point, if any.
+    pub ime: Option<egui::output::IMEOutput>,
+    /// Keyboard hints requested by the focused widget.
+    pub hints: ImeHints,
+    /// Id of the widget that currently owns IME focus, or `None` if none does.
+    pub focused: Option<egui::Id>,
+    /// Text immediately before and after the caret in the focused `TextEdit`,
+    /// seeded into the input so the IME can reconvert it and place candidates.
+    pub surrounding: Option<(String, String)>,
+}
+
+/// Virtual-keyboard hints a widget can request for the hidden `<input>`.
+///
+/// These map onto the HTML `inputmode`/`enterkeyhint` attributes and are
+/// threaded down from egui's platform output so a widget can ask for, e.g., a
+/// numeric keypad with a "Done" return key.
+#[derive(Clone, Copy, Default)]
+pub struct ImeHints {
+    pub keyboard: VirtualKeyboard,
+    pub enter_key: Option<EnterKeyHint>,
+}
+
+/// The kind of soft keyboard to show, mapped to the HTML `inputmode` attribute.
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub enum VirtualKeyboard {
+    /// A standard text keyboard (the `inputmode` attribute is cleared).
+    #[default]
+    Text,
+    Numeric,
+    Decimal,
+    Tel,
+    Email,
+    Url,
+    Search,
+}
+
+impl VirtualKeyboard {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Text => "text",
+            Self::Numeric => "numeric",
+            Self::Decimal => "decimal",
+            Self::Tel => "tel",
+            Self::Email => "email",
+            Self::Url => "url",
+            Self::Search => "search",
+        }
+    }
+}
+
+/// The action label for the soft keyboard's return key (`enterkeyhint`).
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum EnterKeyHint {
+    Enter,
+    Go,
+    Done,
+    Next,
+    Previous,
+    Search,
+    Send,
+}
+
+impl EnterKeyHint {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Enter => "enter",
+            Self::Go => "go",
+            Self::Done => "done",
+            Self::Next => "next",
+            Self::Previous => "previous",
+            Self::Search => "search",
+            Self::Send => "send",
+        }
+    }
+}
+
+/// Authoritative composition state for the hidden input.
+///
+/// `compositionstart`/`update`/`end` and `input` events arrive in an
+/// inconsistent order across browser engines (e.g. WebKit fires `input` before
+/// `compositionend`, Blink after), so a single state variable — rather than
+/// per-event `is_composing()` checks — is used to serialize them. A raw
+/// character is only emitted as [`egui::Event::Text`] while [`Idle`], and the
+/// Gboard suggestion reset is only synthesized at a real commit boundary.
+///
+/// [`Idle`]: CompositionState::Idle
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+enum CompositionState {
+    /// No composition in flight; `input` events are standalone characters.
+    #[default]
+    Idle,
+    /// Between `compositionstart` and `compositionend`; `input` events belong
+    /// to the preedit and are handled by `compositionupdate`.
+    Composing,
+    /// `compositionend` has committed; the next `input` event returns to `Idle`
+    /// and is swallowed if it only echoes the commit, or emitted otherwise.
+    Committing,
+}
+
 pub struct TextAgent {
     input: web_sys::HtmlInputElement,
     prev_ime_output: Cell<Option<egui::output::IMEOutput>>,
+    /// Authoritative state serializing composition and `input` events.
+    state: std::rc::Rc<Cell<CompositionState>>,
+    /// Surrounding-text baseline seeded into the input for reconversion, if any.
+    surrounding: std::rc::Rc<std::cell::RefCell<Option<Baseline>>>,
+    /// Keyboard hints requested by the focused widget, applied before focus.
+    hints: Cell<ImeHints>,
+    /// Id of the widget that currently owns IME focus, to detect context switches.
+    focused_id: Cell<Option<egui::Id>>,
+}
+
+/// The surrounding-text window seeded into the hidden input.
+///
+/// When present, the input isn't empty: it holds a slice of the focused
+/// `TextEdit`'s text around the caret so the IME can offer reconversion and
+/// place its candidate window accurately. Only the *delta* of the input's value
+/// against this baseline is treated as newly committed text.
+struct Baseline {
+    /// The value we seeded (the `before` + `after` context).
+    value: String,
+    /// Caret offset within `value`, in UTF-16 code units.
+    caret_utf16: u32,
 }
 
 impl TextAgent {
@@ -52,23 +176,70 @@ impl TextAgent {
 
         // attach event listeners
 
+        let state = std::rc::Rc::new(Cell::new(CompositionState::Idle));
+        let surrounding: std::rc::Rc<std::cell::RefCell<Option<Baseline>>> =
+            std::rc::Rc::new(std::cell::RefCell::new(None));
+        // The text committed by the most recent `compositionend`, used to
+        // recognise (and swallow) the browser's echo `input` event.
+        let last_commit: std::rc::Rc<std::cell::RefCell<Option<String>>> =
+            std::rc::Rc::new(std::cell::RefCell::new(None));
+
         let on_input = {
             let input = input.clone();
-            move |event: web_sys::InputEvent, runner: &mut AppRunner| {
-                let text = input.value();
-                // Fix android virtual keyboard Gboard
-                // This removes the virtual keyboard's suggestion.
-                if !event.is_composing() {
-                    input.blur().ok();
-                    input.focus().ok();
+            let state = state.clone();
+            let surrounding = surrounding.clone();
+            let last_commit = last_commit.clone();
+            move |_: web_sys::InputEvent, runner: &mut AppRunner| {
+                match state.get() {
+                    // The `input` belongs to an in-flight preedit; the text is
+                    // forwarded by `compositionupdate`, so swallow it here.
+                    CompositionState::Composing => return,
+
+                    // Right after a commit. Some engines fire an `input` echoing
+                    // the committed text (swallow it so it isn't emitted twice);
+                    // others (WebKit, mouse/candidate commits) fire none, so the
+                    // next event here is a genuine keystroke that must fall
+                    // through to be emitted rather than dropped.
+                    CompositionState::Committing => {
+                        state.set(CompositionState::Idle);
+                        let value = input.value();
+                        // Diff against the (rebased) baseline so a seeded
+                        // surrounding context isn't mistaken for new input.
+                        let delta = match surrounding.borrow().as_ref() {
+                            Some(baseline) => inserted_text(&baseline.value, &value).to_owned(),
+                            None => value,
+                        };
+                        let committed = last_commit.borrow_mut().take();
+                        if is_commit_echo(&delta, committed.as_deref()) {
+                            // The browser's echo of the committed text — swallow
+                            // it, keeping the surrounding context for
+                            // reconversion, and clear Gboard's suggestion at the
+                            // commit boundary.
+                            input.blur().ok();
+                            input.focus().ok();
+                            return;
+                        }
+                        // Otherwise fall through: a real character was typed.
+                    }
+
+                    // A standalone character typed without the IME.
+                    CompositionState::Idle => {}
                 }
-                // if `is_composing` is true, then user is using IME, for example: emoji, pinyin, kanji, hangul, etc.
-                // In that case, the browser emits both `input` and `compositionupdate` events,
-                // and we need to ignore the `input` event.
-                if !text.is_empty() && !event.is_composing() {
-                    input.set_value("");
-                    let event = egui::Event::Text(text);
-                    runner.input.raw.events.push(event);
+
+                let value = input.value();
+                // If we seeded surrounding context, only the diff against that
+                // baseline is the newly committed text.
+                let text = match surrounding.borrow().as_ref() {
+                    Some(baseline) => inserted_text(&baseline.value, &value).to_owned(),
+                    None => value,
+                };
+                input.set_value("");
+                surrounding.borrow_mut().take();
+                // Clear Gboard's suggestion on the committed character.
+                input.blur().ok();
+                input.focus().ok();
+                if !text.is_empty() {
+                    runner.input.raw.events.push(egui::Event::Text(text));
                     runner.needs_repaint.repaint_asap();
                 }
             }
@@ -76,8 +247,17 @@ impl TextAgent {
 
         let on_composition_start = {
             let input = input.clone();
+            let state = state.clone();
+            let surrounding = surrounding.clone();
             move |_: web_sys::CompositionEvent, runner: &mut AppRunner| {
-                input.set_value("");
+                // Keep any seeded surrounding text in place so the IME can
+                // reconvert it and place candidates accurately; the preedit is
+                // composed at the caret within it. Only start from empty when we
+                // have no context to preserve.
+                if surrounding.borrow().is_none() {
+                    input.set_value("");
+                }
+                state.set(CompositionState::Composing);
                 let event = egui::Event::Ime(egui::ImeEvent::Enabled);
                 runner.input.raw.events.push(event);
                 // Repaint moves the text agent into place,
@@ -87,8 +267,17 @@ impl TextAgent {
         };
 
         let on_composition_update = {
+            let state = state.clone();
             move |event: web_sys::CompositionEvent, runner: &mut AppRunner| {
+                state.set(CompositionState::Composing);
                 let Some(text) = event.data() else { return };
+                // We could read `selection_start`/`selection_end` here to learn
+                // where the caret sits inside the composing string and which
+                // clause is being converted, but `egui::ImeEvent::Preedit`
+                // carries only the string — there is no field to pass a caret or
+                // segment range through. Emitting those needs a richer
+                // `Preedit` variant in egui itself; until then we forward the
+                // bare composing text.
                 let event = egui::Event::Ime(egui::ImeEvent::Preedit(text));
                 runner.input.raw.events.push(event);
                 runner.needs_repaint.repaint_asap();
@@ -97,9 +286,25 @@ impl TextAgent {
 
         let on_composition_end = {
             let input = input.clone();
+            let state = state.clone();
+            let surrounding = surrounding.clone();
+            let last_commit = last_commit.clone();
             move |event: web_sys::CompositionEvent, runner: &mut AppRunner| {
                 let Some(text) = event.data() else { return };
-                input.set_value("");
+                // Enter `Committing` so a trailing `input` event echoing this
+                // text is recognised and swallowed rather than re-emitted.
+                *last_commit.borrow_mut() = Some(text.clone());
+                // Rebase the surrounding baseline onto the committed text (the
+                // browser replaced the preedit with it) so the echo diffs to
+                // nothing; when we weren't seeding context, just clear the input.
+                match surrounding.borrow_mut().as_mut() {
+                    Some(baseline) => {
+                        baseline.value = input.value();
+                        baseline.caret_utf16 += text.encode_utf16().count() as u32;
+                    }
+                    None => input.set_value(""),
+                }
+                state.set(CompositionState::Committing);
                 let event = egui::Event::Ime(egui::ImeEvent::Commit(text));
                 runner.input.raw.events.push(event);
                 runner.needs_repaint.repaint_asap();
@@ -119,9 +324,39 @@ impl TextAgent {
         Ok(Self {
             input,
             prev_ime_output: Default::default(),
+            state,
+            surrounding,
+            hints: Default::default(),
+            focused_id: Default::default(),
         })
     }
 
+    /// Reconcile the text agent with egui's platform output for this frame.
+    ///
+    /// This is the single entry point `AppRunner::handle_platform_output` calls
+    /// after each update, passing the IME state it read from egui. It aborts any
+    /// composition left over when focus moves between widgets, applies the
+    /// per-widget keyboard hints, seeds the surrounding text for reconversion,
+    /// and moves the hidden input under the caret.
+    pub fn update(
+        &self,
+        runner: &mut AppRunner,
+        update: ImeUpdate,
+        canvas: &web_sys::HtmlCanvasElement,
+        zoom_factor: f32,
+    ) -> Result<(), JsValue> {
+        self.handle_focus_change(runner, update.focused);
+        self.configure(update.hints);
+        // Seed the surrounding context for reconversion, but not mid-composition
+        // where it would clobber the preedit the IME is editing.
+        if let Some((before, after)) = &update.surrounding {
+            if self.state.get() == CompositionState::Idle {
+                self.set_surrounding_text(before, after)?;
+            }
+        }
+        self.move_to(update.ime, canvas, zoom_factor)
+    }
+
     pub fn move_to(
         &self,
         ime: Option<egui::output::IMEOutput>,
@@ -158,10 +393,97 @@ impl TextAgent {
         Ok(())
     }
 
+    /// Record the keyboard hints requested by the focused widget.
+    ///
+    /// A numeric or email field can ask for the matching soft keyboard instead
+    /// of the generic QWERTY one. The attributes are (re)applied in
+    /// [`focus`](Self::focus), since the browser reads them when it summons the
+    /// keyboard on focus. Driven from [`update`](Self::update).
+    pub fn configure(&self, hints: ImeHints) {
+        self.hints.set(hints);
+    }
+
+    /// Apply the recorded `inputmode`/`enterkeyhint` attributes to the input.
+    fn apply_hints(&self) -> Result<(), JsValue> {
+        let hints = self.hints.get();
+        match hints.keyboard {
+            VirtualKeyboard::Text => self.input.remove_attribute("inputmode")?,
+            kw => self.input.set_attribute("inputmode", kw.as_str())?,
+        }
+        match hints.enter_key {
+            None => self.input.remove_attribute("enterkeyhint")?,
+            Some(hint) => self.input.set_attribute("enterkeyhint", hint.as_str())?,
+        }
+        Ok(())
+    }
+
+    /// Abort any in-flight IME composition before the editing context changes.
+    ///
+    /// Call this when the agent is blurred, when focus moves to a different
+    /// `TextEdit`, or when the user presses Escape mid-conversion. It always
+    /// clears the hidden input, drops the surrounding-text baseline and the
+    /// cached IME output, and returns to `Idle`. If a composition was active it
+    /// also emits an empty preedit followed by [`egui::ImeEvent::Disabled`] so
+    /// egui drops the stale composing string before it can leak into the next
+    /// field; otherwise it emits no events.
+    pub fn reset_composition(&self, runner: &mut AppRunner) {
+        let had_preedit = self.state.get() != CompositionState::Idle;
+
+        self.input.set_value("");
+        self.surrounding.borrow_mut().take();
+        self.prev_ime_output.set(None);
+        self.state.set(CompositionState::Idle);
+
+        if had_preedit {
+            runner
+                .input
+                .raw
+                .events
+                .push(egui::Event::Ime(egui::ImeEvent::Preedit(String::new())));
+            runner
+                .input
+                .raw
+                .events
+                .push(egui::Event::Ime(egui::ImeEvent::Disabled));
+            runner.needs_repaint.repaint_asap();
+        }
+    }
+
+    /// Reset the composition when focus moves to a different widget.
+    ///
+    /// Called from [`update`](Self::update) with the currently focused widget's
+    /// id; when it changes (including to `None` on blur) we abort any
+    /// composition left over from the previous widget so a half-converted
+    /// preedit can't be double-committed into the newly focused one.
+    pub fn handle_focus_change(&self, runner: &mut AppRunner, focused: Option<egui::Id>) {
+        if self.focused_id.get() != focused {
+            self.focused_id.set(focused);
+            self.reset_composition(runner);
+        }
+    }
+
+    /// Seed the hidden input with the text surrounding the caret.
+    ///
+    /// Giving the IME the context around the caret lets it reconvert
+    /// already-entered text and place its candidate window accurately. `before`
+    /// is the text left of the caret and `after` the text to its right; the
+    /// selection is set to the caret between them, and only the delta against
+    /// this baseline is later treated as committed text.
+    pub fn set_surrounding_text(&self, before: &str, after: &str) -> Result<(), JsValue> {
+        let value = format!("{before}{after}");
+        let caret_utf16 = before.encode_utf16().count() as u32;
+        self.input.set_value(&value);
+        self.input.set_selection_range(caret_utf16, caret_utf16)?;
+        *self.surrounding.borrow_mut() = Some(Baseline { value, caret_utf16 });
+        Ok(())
+    }
+
     pub fn set_focus(&self, on: bool) {
         if on {
             self.focus();
         } else {
+            // Any pending composition is discarded via `handle_focus_change`
+            // when egui reports the widget losing focus on the next frame.
             self.blur();
         }
     }
@@ -177,6 +499,15 @@ impl TextAgent {
 
         log::trace!("Focusing text agent");
 
+        // Set the keyboard hints before focus so the browser picks the right
+        // soft-keyboard layout and return-key label when it opens.
+        if let Err(err) = self.apply_hints() {
+            log::error!(
+                "failed to apply keyboard hints: {}",
+                super::string_from_js_value(&err)
+            );
+        }
+
         if let Err(err) = self.input.focus() {
             log::error!("failed to set focus: {}", super::string_from_js_value(&err));
         };
@@ -201,6 +532,41 @@ impl Drop for TextAgent {
     }
 }
 
+/// Whether a post-commit `input` event is just the browser echoing the text we
+/// already committed, in which case it should be swallowed rather than emitted
+/// a second time. An empty delta (no new text) is always an echo.
+fn is_commit_echo(delta: &str, committed: Option<&str>) -> bool {
+    delta.is_empty() || committed == Some(delta)
+}
+
+/// Return the slice of `current` that was inserted relative to `baseline`.
+///
+/// Both strings share a common prefix and suffix (the surrounding context we
+/// seeded); what's between them is the text the user just committed. Prefix and
+/// suffix lengths are measured on `char` boundaries so the returned slice is
+/// always valid UTF-8.
+fn inserted_text<'a>(baseline: &str, current: &'a str) -> &'a str {
+    let mut prefix = 0;
+    for (a, b) in baseline.chars().zip(current.chars()) {
+        if a != b {
+            break;
+        }
+        prefix += b.len_utf8();
+    }
+
+    // Don't let the suffix reach back past the prefix we already consumed.
+    let max_suffix = current.len().min(baseline.len()).saturating_sub(prefix);
+    let mut suffix = 0;
+    for (a, b) in baseline.chars().rev().zip(current.chars().rev()) {
+        if a != b || suffix + b.len_utf8() > max_suffix {
+            break;
+        }
+        suffix += b.len_utf8();
+    }
+
+    &current[prefix..current.len() - suffix]
+}
+
 /// Returns `true` if the app is likely running on a mobile device on navigator Safari.
 fn is_mobile_safari() -> bool {
     (|| {
@@ -213,3 +579,56 @@ fn is_mobile_safari() -> bool {
     })()
     .unwrap_or(false)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inserted_text_finds_appended_suffix() {
+        assert_eq!(inserted_text("", "a"), "a");
+        assert_eq!(inserted_text("ab", "abc"), "c");
+    }
+
+    #[test]
+    fn inserted_text_finds_insertion_in_the_middle() {
+        // Baseline is `before` + `after`; the insert lands between them.
+        assert_eq!(inserted_text("ac", "abc"), "b");
+        assert_eq!(inserted_text("foobar", "fooXXXbar"), "XXX");
+    }
+
+    #[test]
+    fn inserted_text_handles_multibyte_baselines() {
+        // 3-byte CJK characters either side of the caret.
+        assert_eq!(inserted_text("漢字", "漢は字"), "は");
+        // 4-byte astral characters (emoji) on the boundaries.
+        assert_eq!(inserted_text("🦀🦀", "🦀🎉🦀"), "🎉");
+        // Hangul syllable inserted after existing context.
+        assert_eq!(inserted_text("가", "가나"), "나");
+    }
+
+    #[test]
+    fn inserted_text_is_empty_when_unchanged() {
+        assert_eq!(inserted_text("漢字", "漢字"), "");
+    }
+
+    #[test]
+    fn inserted_text_suffix_does_not_overlap_prefix() {
+        // A repeated character must not let the suffix reach back past the
+        // prefix and slice a negative-length range.
+        assert_eq!(inserted_text("aa", "aaa"), "a");
+        assert_eq!(inserted_text("ああ", "あああ"), "あ");
+    }
+
+    #[test]
+    fn commit_echo_detection() {
+        // The browser echoing the committed text is an echo.
+        assert!(is_commit_echo("する", Some("する")));
+        // No new text is always an echo.
+        assert!(is_commit_echo("", Some("する")));
+        assert!(is_commit_echo("", None));
+        // A genuinely different keystroke is not an echo.
+        assert!(!is_commit_echo("a", Some("する")));
+        assert!(!is_commit_echo("a", None));
+    }
+}